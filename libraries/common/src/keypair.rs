@@ -5,6 +5,7 @@ use solana_sdk::signature::Signature;
 use solana_sdk::signature::SignerError;
 use std::fmt;
 use std::fmt::Display;
+use std::sync::Arc;
 
 /// A tokio compatible wrapper for `anchor_client::solana_sdk::signature::Keypair`
 ///
@@ -19,13 +20,34 @@ pub struct Keypair {
     keypair: [u8; 64],
 }
 
-pub trait SendableSigner: Send + Sync + Signer + PartialEq {
-    fn to_keypair(&self) -> signature::Keypair;
+/// A signer that can be sent between threads (and across the async boundary)
+/// and mixed with other signers in a single transaction.
+///
+/// This is intentionally kept object-safe (no `PartialEq` supertrait) so
+/// callers can hold a `&[Arc<dyn SendableSigner>]` mixing local `Keypair`s,
+/// remote/hardware wallets, and other custom signers. Use [`signers_eq`]
+/// if you need to compare two signers for equality.
+pub trait SendableSigner: Send + Sync + Signer {
+    fn to_keypair(&self) -> std::result::Result<signature::Keypair, SignerError>;
+
+    /// Upcasts this signer to a plain `Signer` trait object so it can be
+    /// partial-signed alongside other heterogeneous signers.
+    fn as_signer(self: Arc<Self>) -> Arc<dyn Signer>;
+}
+
+/// Compares two `SendableSigner`s by the public key they sign for, since the
+/// trait itself can no longer require `PartialEq` and remain object-safe.
+pub fn signers_eq(a: &dyn SendableSigner, b: &dyn SendableSigner) -> bool {
+    a.try_pubkey().ok() == b.try_pubkey().ok()
 }
 
 impl SendableSigner for Keypair {
-    fn to_keypair(self: &Self) -> signature::Keypair {
-        signature::Keypair::from_bytes(&self.keypair).unwrap()
+    fn to_keypair(self: &Self) -> std::result::Result<signature::Keypair, SignerError> {
+        signature::Keypair::from_bytes(&self.keypair).map_err(|e| SignerError::Custom(e.to_string()))
+    }
+
+    fn as_signer(self: Arc<Self>) -> Arc<dyn Signer> {
+        self
     }
 }
 
@@ -1,43 +1,287 @@
+use crate::keypair::SendableSigner;
 use anchor_lang::AccountDeserialize;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use base64::{prelude::BASE64_STANDARD, Engine};
-use solana_account_decoder::UiAccountEncoding;
+use solana_account_decoder::{UiAccountEncoding, UiDataSliceConfig};
+use solana_address_lookup_table_program::state::AddressLookupTable;
 use solana_client::{
     nonblocking::rpc_client::RpcClient,
     rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcSendTransactionConfig},
-    rpc_filter::RpcFilterType,
+    rpc_filter::{Memcmp, RpcFilterType},
     rpc_request::RpcRequest,
     rpc_response::{RpcResult, RpcSimulateTransactionResult},
 };
 use solana_sdk::{
-    account::Account, commitment_config::CommitmentConfig, instruction::Instruction,
-    message::Message, pubkey::Pubkey, signature::{Keypair, Signature, Signer},
-    transaction::Transaction,
+    account::Account, address_lookup_table_account::AddressLookupTableAccount,
+    commitment_config::CommitmentConfig, compute_budget::ComputeBudgetInstruction,
+    instruction::Instruction,
+    message::{v0, Message, VersionedMessage},
+    nonce::state::{Data as NonceData, State as NonceState, Versions as NonceVersions},
+    pubkey::Pubkey,
+    signature::{Signature, Signer},
+    system_instruction,
+    transaction::{SerializableTransaction, Transaction, VersionedTransaction},
 };
 use solana_transaction_status::UiTransactionEncoding;
 use std::sync::Arc;
+use std::time::Duration;
 
+/// Builds and partial-signs a legacy transaction against a heterogeneous set
+/// of signers (local keypairs, remote/hardware wallets, ...).
+///
+/// Unlike the raw-byte-keypair version this replaces, a signer that fails to
+/// sign (e.g. a hardware wallet rejected by the user) surfaces as an `Err`
+/// instead of panicking.
 pub async fn build_txn(
     client: &RpcClient,
     instructions: &[Instruction],
     fee_payer: &Pubkey,
-    signing_keypairs_sendable: &Vec<Arc<[u8; 64]>>,
+    signing_keypairs_sendable: &[Arc<dyn SendableSigner>],
 ) -> Result<Transaction> {
-    let blockhash = client.get_latest_blockhash().await.unwrap();
-    let message = Message::new_with_blockhash(&instructions, Some(fee_payer), &blockhash);
+    let blockhash = client.get_latest_blockhash().await?;
+    let message = Message::new_with_blockhash(instructions, Some(fee_payer), &blockhash);
     let mut transaction = Transaction::new_unsigned(message);
-    let mut signing_keypairs: Vec<Arc<dyn Signer>> = Vec::new();
-    for kp in signing_keypairs_sendable.iter() {
-        signing_keypairs.push(Arc::new(Keypair::from_bytes(kp.as_ref()).unwrap()));
+
+    let signing_keypairs: Vec<Arc<dyn Signer>> = signing_keypairs_sendable
+        .iter()
+        .cloned()
+        .map(SendableSigner::as_signer)
+        .collect();
+
+    transaction.try_partial_sign(&signing_keypairs, blockhash)?;
+    Ok(transaction)
+}
+
+/// Builds and partial-signs a v0 transaction against one or more address
+/// lookup tables, for instruction sets that touch too many accounts to fit
+/// in a legacy message.
+pub async fn build_versioned_txn(
+    client: &RpcClient,
+    instructions: &[Instruction],
+    fee_payer: &Pubkey,
+    signing_keypairs_sendable: &[Arc<dyn SendableSigner>],
+    lookup_table_addresses: &[Pubkey],
+) -> Result<VersionedTransaction> {
+    let blockhash = client.get_latest_blockhash().await?;
+
+    let lookup_table_accounts = get_multiple_accounts(client, lookup_table_addresses).await?;
+    let mut address_lookup_table_accounts = Vec::with_capacity(lookup_table_addresses.len());
+    for (key, account) in lookup_table_addresses.iter().zip(lookup_table_accounts) {
+        let account =
+            account.ok_or_else(|| anyhow!("address lookup table {key} not found"))?;
+        let table = AddressLookupTable::deserialize(&account.data)?;
+        address_lookup_table_accounts.push(AddressLookupTableAccount {
+            key: *key,
+            addresses: table.addresses.to_vec(),
+        });
+    }
+
+    let message = VersionedMessage::V0(v0::Message::try_compile(
+        fee_payer,
+        instructions,
+        &address_lookup_table_accounts,
+        blockhash,
+    )?);
+
+    // Built with default (empty) signatures rather than `VersionedTransaction::try_new`,
+    // which demands the complete signer set up front -- we only want to fill in
+    // the signatures for the signers we were actually given.
+    let num_required_signatures = message.header().num_required_signatures as usize;
+    let mut transaction = VersionedTransaction {
+        signatures: vec![Signature::default(); num_required_signatures],
+        message,
+    };
+    let message_data = transaction.message.serialize();
+
+    for signer in signing_keypairs_sendable {
+        let signer_pubkey = signer.try_pubkey()?;
+        let index = transaction
+            .message
+            .static_account_keys()
+            .iter()
+            .position(|key| key == &signer_pubkey)
+            .filter(|&index| index < transaction.signatures.len())
+            .ok_or_else(|| {
+                anyhow!("{signer_pubkey} is not a required signer of this transaction")
+            })?;
+        transaction.signatures[index] = signer.try_sign_message(&message_data)?;
+    }
+
+    Ok(transaction)
+}
+
+/// Fetches a durable nonce account and returns its stored `Data`, after
+/// checking that `nonce_authority` is actually authorized over it.
+pub async fn get_nonce_account_data(
+    client: &RpcClient,
+    nonce_pubkey: &Pubkey,
+    nonce_authority: &Pubkey,
+) -> Result<NonceData> {
+    let account = client
+        .get_account_with_commitment(nonce_pubkey, CommitmentConfig::processed())
+        .await?
+        .value
+        .ok_or_else(|| anyhow!("nonce account {nonce_pubkey} not found"))?;
+
+    // On-chain nonce accounts store a `Versions` wrapper (`Legacy`/`Current`)
+    // around the `State`, not a bare `State` -- deserializing straight into
+    // `State` misreads every field behind the version tag.
+    let versions: NonceVersions = bincode::deserialize(&account.data)?;
+    match versions.convert_to_current() {
+        NonceState::Initialized(data) => {
+            if &data.authority != nonce_authority {
+                return Err(anyhow!(
+                    "{nonce_authority} is not the authority of nonce account {nonce_pubkey}"
+                ));
+            }
+            Ok(data)
+        }
+        NonceState::Uninitialized => {
+            Err(anyhow!("nonce account {nonce_pubkey} is uninitialized"))
+        }
+    }
+}
+
+/// Builds and partial-signs a transaction using a durable nonce as the
+/// recent blockhash instead of `get_latest_blockhash`, so the signed
+/// transaction doesn't expire and can be submitted offline or much later
+/// (e.g. scheduled execution, multisig rounds).
+pub async fn build_txn_with_nonce(
+    client: &RpcClient,
+    instructions: &[Instruction],
+    fee_payer: &Pubkey,
+    signing_keypairs_sendable: &[Arc<dyn SendableSigner>],
+    nonce_pubkey: &Pubkey,
+    nonce_authority: &Pubkey,
+) -> Result<Transaction> {
+    let nonce_data = get_nonce_account_data(client, nonce_pubkey, nonce_authority).await?;
+
+    let mut all_instructions = Vec::with_capacity(instructions.len() + 1);
+    all_instructions.push(system_instruction::advance_nonce_account(
+        nonce_pubkey,
+        nonce_authority,
+    ));
+    all_instructions.extend_from_slice(instructions);
+
+    let message =
+        Message::new_with_blockhash(&all_instructions, Some(fee_payer), &nonce_data.blockhash());
+    let mut transaction = Transaction::new_unsigned(message);
+
+    let signing_keypairs: Vec<Arc<dyn Signer>> = signing_keypairs_sendable
+        .iter()
+        .cloned()
+        .map(SendableSigner::as_signer)
+        .collect();
+
+    transaction.try_partial_sign(&signing_keypairs, nonce_data.blockhash())?;
+    Ok(transaction)
+}
+
+/// Simulates `instructions` to estimate the compute units they'll consume,
+/// then returns a new instruction vector with `set_compute_unit_limit` and
+/// `set_compute_unit_price` prepended, so callers don't have to guess a
+/// compute budget by hand.
+///
+/// `compute_unit_limit_margin_pct` is added on top of the simulated
+/// `units_consumed` (e.g. `10` for a 10% safety margin).
+/// `compute_unit_price_micro_lamports` is the priority fee to pay per
+/// compute unit; callers can derive it however they like (a fixed value, or
+/// a percentile over `getRecentPrioritizationFees`).
+pub async fn with_compute_budget(
+    client: &RpcClient,
+    instructions: &[Instruction],
+    fee_payer: &Pubkey,
+    compute_unit_price_micro_lamports: u64,
+    compute_unit_limit_margin_pct: u64,
+) -> Result<Vec<Instruction>> {
+    let blockhash = client.get_latest_blockhash().await?;
+    let message = Message::new_with_blockhash(instructions, Some(fee_payer), &blockhash);
+    let transaction = Transaction::new_unsigned(message);
+
+    let simulation =
+        simulate_transaction(client, &transaction, false, CommitmentConfig::processed()).await?;
+    if let Some(err) = simulation.err {
+        return Err(anyhow!(
+            "compute unit simulation failed: {err}\n{}",
+            simulation.logs.unwrap_or_default().join("\n")
+        ));
+    }
+    let units_consumed = simulation
+        .units_consumed
+        .ok_or_else(|| anyhow!("simulation result did not include units_consumed"))?;
+    let compute_unit_limit =
+        units_consumed.saturating_mul(100 + compute_unit_limit_margin_pct) / 100;
+
+    let mut with_budget = Vec::with_capacity(instructions.len() + 2);
+    with_budget.push(ComputeBudgetInstruction::set_compute_unit_limit(
+        compute_unit_limit as u32,
+    ));
+    with_budget.push(ComputeBudgetInstruction::set_compute_unit_price(
+        compute_unit_price_micro_lamports,
+    ));
+    with_budget.extend_from_slice(instructions);
+    Ok(with_budget)
+}
+
+/// Base64-encodes a (possibly only partially) signed transaction so it can
+/// be handed between machines or co-signers before final submission.
+pub fn serialize_partially_signed(transaction: &Transaction) -> Result<String> {
+    Ok(BASE64_STANDARD.encode(bincode::serialize(transaction)?))
+}
+
+/// Decodes a transaction produced by [`serialize_partially_signed`], checks
+/// that its message and any already-present signatures haven't been
+/// tampered with in transit, then adds `signers`' signatures over the same
+/// (already-present) blockhash and returns the (possibly now fully signed)
+/// transaction.
+pub fn deserialize_and_add_signatures(
+    encoded: &str,
+    signers: &[Arc<dyn SendableSigner>],
+) -> Result<Transaction> {
+    let mut transaction: Transaction = bincode::deserialize(&BASE64_STANDARD.decode(encoded)?)?;
+    let message_data = transaction.message.serialize();
+
+    for (index, signature) in transaction.signatures.iter().enumerate() {
+        if *signature == Signature::default() {
+            continue;
+        }
+        let signer_pubkey = transaction
+            .message
+            .account_keys
+            .get(index)
+            .ok_or_else(|| anyhow!("transaction message has no signer at index {index}"))?;
+        if !signature.verify(signer_pubkey.as_ref(), &message_data) {
+            return Err(anyhow!(
+                "existing signature at index {index} does not match the transaction message"
+            ));
+        }
+    }
+
+    for signer in signers {
+        let signer_pubkey = signer.try_pubkey()?;
+        // `account_keys` also holds non-signer accounts, but `signatures` only
+        // has a slot per required signer, so a match past `signatures.len()`
+        // (a referenced-but-non-signer account) is not a signer we can fill in.
+        let index = transaction
+            .message
+            .account_keys
+            .iter()
+            .position(|key| key == &signer_pubkey)
+            .filter(|&index| index < transaction.signatures.len())
+            .ok_or_else(|| {
+                anyhow!("{signer_pubkey} is not a required signer of this transaction")
+            })?;
+        transaction.signatures[index] = signer.try_sign_message(&message_data)?;
     }
 
-    transaction
-        .try_partial_sign(&signing_keypairs, blockhash)
-        .unwrap();
     Ok(transaction)
 }
 
-pub async fn send_txn(client: &RpcClient, txn: &Transaction, skip_preflight: bool) -> Result<Signature> {
+pub async fn send_txn<T: SerializableTransaction>(
+    client: &RpcClient,
+    txn: &T,
+    skip_preflight: bool,
+) -> Result<Signature> {
     Ok(client.send_and_confirm_transaction_with_spinner_and_config(
         txn,
         CommitmentConfig::confirmed(),
@@ -48,27 +292,70 @@ pub async fn send_txn(client: &RpcClient, txn: &Transaction, skip_preflight: boo
     ).await?)
 }
 
+/// Builds, sends, and confirms a transaction, retrying on a fresh blockhash
+/// when the previous attempt's blockhash expired before landing (a transient
+/// failure mode in long-running bots, not a reason to give up).
+///
+/// Re-signs with `signing_keypairs_sendable` on every attempt since a new
+/// blockhash invalidates the prior signatures. Backs off exponentially
+/// between attempts, up to `max_attempts` tries total.
+pub async fn send_and_confirm_with_retry(
+    client: &RpcClient,
+    instructions: &[Instruction],
+    fee_payer: &Pubkey,
+    signing_keypairs_sendable: &[Arc<dyn SendableSigner>],
+    max_attempts: u32,
+) -> Result<Signature> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let transaction =
+            build_txn(client, instructions, fee_payer, signing_keypairs_sendable).await?;
+
+        match send_txn(client, &transaction, false).await {
+            Ok(signature) => return Ok(signature),
+            Err(err) if attempt < max_attempts && is_blockhash_expired(&err) => {
+                tokio::time::sleep(Duration::from_millis(200 * 2u64.pow(attempt - 1))).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Best-effort check for the RPC's "blockhash not found"/expired-blockhash
+/// errors, which are the only failure modes [`send_and_confirm_with_retry`]
+/// treats as retryable.
+fn is_blockhash_expired(err: &anyhow::Error) -> bool {
+    let message = err.to_string();
+    message.contains("BlockhashNotFound")
+        || message.contains("Blockhash not found")
+        || message.contains("block height exceeded")
+}
+
 pub async fn simulate_transaction(
     client: &RpcClient,
     transaction: &Transaction,
     sig_verify: bool,
     cfg: CommitmentConfig,
-) -> RpcResult<RpcSimulateTransactionResult> {
-    let serialized = bincode::serialize(transaction)
-        .map_err(|e| (format!("Serialization failed: {e}")))
-        .unwrap();
+) -> Result<RpcSimulateTransactionResult> {
+    let serialized = bincode::serialize(transaction)?;
     let serialized_encoded = BASE64_STANDARD.encode(serialized);
-    println!("{}", serialized_encoded);
 
-    client.send(
-        RpcRequest::SimulateTransaction,
-        serde_json::json!([serialized_encoded, {
-            "sigVerify": sig_verify, "commitment": cfg.commitment, "encoding": Some(UiTransactionEncoding::Base64)
-        }]),
-    ).await
+    let response: RpcResult<RpcSimulateTransactionResult> = client
+        .send(
+            RpcRequest::SimulateTransaction,
+            serde_json::json!([serialized_encoded, {
+                "sigVerify": sig_verify, "commitment": cfg.commitment, "encoding": Some(UiTransactionEncoding::Base64)
+            }]),
+        )
+        .await;
+    Ok(response?.value)
 }
 
-pub async fn send_without_confirm_txn(client: &RpcClient, txn: &Transaction) -> Result<Signature> {
+pub async fn send_without_confirm_txn<T: SerializableTransaction>(
+    client: &RpcClient,
+    txn: &T,
+) -> Result<Signature> {
     Ok(client.send_transaction_with_config(
         txn,
         RpcSendTransactionConfig {
@@ -99,7 +386,7 @@ pub async fn get_anchor_account<T: AccountDeserialize>(
         .value
     {
         let mut data: &[u8] = &account.data;
-        let ret = T::try_deserialize(&mut data).unwrap();
+        let ret = T::try_deserialize(&mut data)?;
         Ok(Some(ret))
     } else {
         Ok(None)
@@ -113,10 +400,49 @@ pub async fn get_multiple_accounts(
     Ok(client.get_multiple_accounts(pubkeys).await?)
 }
 
+/// Builds a `memcmp` filter comparing the raw bytes at `offset`.
+pub fn memcmp_filter(offset: usize, bytes: &[u8]) -> RpcFilterType {
+    RpcFilterType::Memcmp(Memcmp::new_raw_bytes(offset, bytes.to_vec()))
+}
+
+/// Builds a `memcmp` filter comparing the pubkey at `offset`, e.g. to find
+/// every account a given authority/owner pubkey appears in.
+pub fn memcmp_pubkey_filter(offset: usize, pubkey: &Pubkey) -> RpcFilterType {
+    memcmp_filter(offset, pubkey.as_ref())
+}
+
+/// Builds a `dataSize` filter, e.g. to narrow a program's accounts down to
+/// one Anchor account type by its serialized size.
+pub fn data_size_filter(len: u64) -> RpcFilterType {
+    RpcFilterType::DataSize(len)
+}
+
+/// Builds a `memcmp` filter matching an Anchor 8-byte account discriminator
+/// at offset 0.
+pub fn anchor_discriminator_filter(discriminator: &[u8; 8]) -> RpcFilterType {
+    memcmp_filter(0, discriminator)
+}
+
+/// Pairs an Anchor discriminator filter with a `memcmp` filter on one of
+/// that account type's fields (`field_offset` counted from the start of the
+/// account's own data, i.e. after the 8-byte discriminator), e.g. to locate
+/// every Raydium pool/position of a given type owned by a given authority.
+pub fn anchor_account_field_filters(
+    discriminator: &[u8; 8],
+    field_offset: usize,
+    field_bytes: &[u8],
+) -> Vec<RpcFilterType> {
+    vec![
+        anchor_discriminator_filter(discriminator),
+        memcmp_filter(8 + field_offset, field_bytes),
+    ]
+}
+
 pub async fn get_program_accounts_with_filters(
     client: &RpcClient,
     program: Pubkey,
     filters: Option<Vec<RpcFilterType>>,
+    data_slice: Option<UiDataSliceConfig>,
 ) -> Result<Vec<(Pubkey, Account)>> {
     let accounts = client
         .get_program_accounts_with_config(
@@ -125,12 +451,27 @@ pub async fn get_program_accounts_with_filters(
                 filters,
                 account_config: RpcAccountInfoConfig {
                     encoding: Some(UiAccountEncoding::Base64Zstd),
+                    data_slice,
                     ..RpcAccountInfoConfig::default()
                 },
                 with_context: Some(false),
             },
         )
-        .await
-        .unwrap();
+        .await?;
     Ok(accounts)
 }
+
+/// Slices an already-fetched account list into `page_size`-sized pages
+/// (0-indexed), since `getProgramAccounts` has no server-side cursor of its
+/// own. Pair with a `data_slice` of just the discriminator/keys in
+/// [`get_program_accounts_with_filters`] to keep a large Raydium program's
+/// accounts cheap to page through.
+pub fn paginate_program_accounts(
+    accounts: &[(Pubkey, Account)],
+    page_size: usize,
+    page: usize,
+) -> &[(Pubkey, Account)] {
+    let start = page.saturating_mul(page_size).min(accounts.len());
+    let end = start.saturating_add(page_size).min(accounts.len());
+    &accounts[start..end]
+}